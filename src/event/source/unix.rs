@@ -22,6 +22,133 @@ const WAKE_TOKEN: Token = Token(2);
 const TTY_BUFFER_SIZE: usize = 8_192;
 const TTY_BUFFER_THRESHOLD: usize = 512;
 
+/// States of the structural (phase-one) scanner that walks the raw TTY byte
+/// stream looking for the *boundary* of the next complete escape/CSI/OSC
+/// sequence, or a complete UTF-8 scalar, without assigning any meaning to
+/// the bytes it sees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// No sequence in progress; the next byte starts a new one.
+    Ground,
+    /// Just saw ESC (`0x1B`).
+    Escape,
+    /// Just saw `ESC [`; waiting for the first parameter, intermediate or
+    /// final byte of a CSI sequence.
+    CsiEntry,
+    /// Accumulating CSI parameter bytes (`0x30..=0x3F`).
+    CsiParam,
+    /// Accumulating CSI intermediate bytes (`0x20..=0x2F`).
+    CsiIntermediate,
+    /// Accumulating an OSC string, waiting for its BEL or ST terminator.
+    OscString,
+    /// Just saw `ESC O` (SS3); waiting for exactly one more byte, the final
+    /// byte that picks out e.g. an F1-F4 key or an application-cursor-mode
+    /// arrow key.
+    EscapeO,
+    /// Just saw `ESC [ M` (the start of legacy/normal mouse tracking);
+    /// waiting for the `remaining` raw, un-escaped data bytes (button, x,
+    /// y) that make up the rest of the report.
+    MouseLegacy(u8),
+    /// Accumulating the remaining continuation bytes of a multi-byte UTF-8
+    /// scalar.
+    Utf8Continuation(u8),
+}
+
+/// Scans `buffer` for the end of the next complete sequence, advancing a
+/// small [`State`] machine byte by byte.
+///
+/// Returns `Some(len)` once `buffer[..len]` is known to hold exactly one
+/// complete sequence (or standalone byte). Returns `None` if `buffer` ends
+/// in the middle of a sequence and `more` is `true`, meaning more bytes are
+/// expected and the caller should retain what it has and wait for them. If
+/// `more` is `false` the scanner treats the buffer it was given as final and
+/// reports whatever it has accumulated as complete, mirroring how
+/// `parse_event` itself falls back when no further input is coming.
+///
+/// An unexpected byte part-way through a sequence aborts back to `Ground`
+/// and the malformed lead byte(s) are reported as their own, standalone
+/// span; they're handed to phase two like anything else and will most
+/// likely come back as a parse error.
+///
+/// SS3 (`ESC O <final>`, used for F1-F4 and application-cursor-mode arrow
+/// keys) and legacy mouse tracking (`ESC [ M` followed by three raw data
+/// bytes) both need a fixed number of lookahead bytes beyond what the
+/// generic CSI/escape rules would consume; `EscapeO` and `MouseLegacy`
+/// exist specifically to wait for them instead of closing the span early.
+fn scan_sequence(buffer: &[u8], more: bool) -> Option<usize> {
+    let mut state = State::Ground;
+
+    for (index, &byte) in buffer.iter().enumerate() {
+        state = match (state, byte) {
+            (State::Ground, 0x1B) => State::Escape,
+            (State::Ground, byte) if byte >= 0xF0 => State::Utf8Continuation(3),
+            (State::Ground, byte) if byte >= 0xE0 => State::Utf8Continuation(2),
+            (State::Ground, byte) if byte >= 0xC0 => State::Utf8Continuation(1),
+            (State::Ground, _) => return Some(index + 1),
+
+            (State::Utf8Continuation(1), byte) if byte & 0xC0 == 0x80 => return Some(index + 1),
+            (State::Utf8Continuation(remaining), byte) if byte & 0xC0 == 0x80 => {
+                State::Utf8Continuation(remaining - 1)
+            }
+            // Not a continuation byte after all: abort and report only the
+            // (malformed) lead byte(s) seen so far.
+            (State::Utf8Continuation(_), _) => return Some(index),
+
+            (State::Escape, b'[') => State::CsiEntry,
+            (State::Escape, b']') => State::OscString,
+            (State::Escape, b'O') => State::EscapeO,
+            // Alt+<non-ASCII char>: the byte after ESC is itself a UTF-8
+            // lead byte, so dispatch into the same continuation tracking
+            // `Ground` uses rather than closing the span right here.
+            (State::Escape, byte) if byte >= 0xF0 => State::Utf8Continuation(3),
+            (State::Escape, byte) if byte >= 0xE0 => State::Utf8Continuation(2),
+            (State::Escape, byte) if byte >= 0xC0 => State::Utf8Continuation(1),
+            (State::Escape, _) => return Some(index + 1),
+
+            // SS3 sequences are always exactly `ESC O <final>`.
+            (State::EscapeO, _) => return Some(index + 1),
+
+            // Legacy/normal mouse tracking: `'M'` right after `ESC [` isn't
+            // a CSI final byte, it's the start of three raw data bytes that
+            // aren't part of the CSI grammar (unlike SGR mouse, which enters
+            // `CsiParam` via the leading `<` and closes on `M`/`m` like any
+            // other CSI sequence).
+            (State::CsiEntry, b'M') => State::MouseLegacy(3),
+            (State::CsiEntry, 0x20..=0x2F) => State::CsiIntermediate,
+            (State::CsiEntry, 0x30..=0x3F) => State::CsiParam,
+            (State::CsiEntry, 0x40..=0x7E) => return Some(index + 1),
+            (State::CsiEntry, _) => return Some(index),
+
+            (State::MouseLegacy(1), _) => return Some(index + 1),
+            (State::MouseLegacy(remaining), _) => State::MouseLegacy(remaining - 1),
+
+            (State::CsiParam, 0x20..=0x2F) => State::CsiIntermediate,
+            (State::CsiParam, 0x30..=0x3F) => State::CsiParam,
+            (State::CsiParam, 0x40..=0x7E) => return Some(index + 1),
+            (State::CsiParam, _) => return Some(index),
+
+            (State::CsiIntermediate, 0x20..=0x2F) => State::CsiIntermediate,
+            (State::CsiIntermediate, 0x40..=0x7E) => return Some(index + 1),
+            (State::CsiIntermediate, _) => return Some(index),
+
+            // BEL terminates an OSC string on its own. Any ESC inside it
+            // falls back through `Escape` without checking for the '\' of
+            // ST: the `Escape` arms decide where the span ends, so the usual
+            // `ESC \` closes it on the '\', while e.g. `ESC [` carries on as
+            // a CSI sequence.
+            (State::OscString, 0x07) => return Some(index + 1),
+            (State::OscString, 0x1B) => State::Escape,
+            (State::OscString, _) => State::OscString,
+        };
+    }
+
+    if more {
+        None
+    } else {
+        Some(buffer.len())
+    }
+}
+
 /// Creates a new pipe and returns `(read, write)` file descriptors.
 fn pipe() -> Result<(FileDesc, FileDesc)> {
     let (read_fd, write_fd) = unsafe {
@@ -167,62 +294,41 @@ impl EventSource for UnixInternalEventSource {
 
                                     let mut consumed_bytes = 0;
 
-                                    // Loop until all bytes are processed
+                                    // Loop until all bytes are processed (or we hit one that's
+                                    // still incomplete and waiting on more input).
                                     while byte_count_to_process > 0 {
-                                        // We have to use this loop, because `parse_event`, `parse_csi`, ...
-                                        // functions are not efficient. They're matching first bytes and also
-                                        // last byte (csi xterm mouse where last 'm'/'M' says up/down), etc.
-                                        //
-                                        // In other words, we try to parse with 1 byte, 2 bytes, 3 bytes,
-                                        // 4 bytes, 5 bytes, ... until the parser error or returns an event.
-                                        //
-                                        // If we will switch to the anes parser (two phases parsing), we can
-                                        // easily avoid this inner for loop. The reason is that the anes parser
-                                        // knows how to parse csi sequence without a meaning (knows when the csi
-                                        // sequence ends) and then it gives it a meaning. We do not need to
-                                        // advance with byte by byte here.
-                                        for i in 1..=byte_count_to_process {
-                                            // More bytes to read? Yes if we're not at the end of the buffer
-                                            // or poll says that there's more and we're at the end of the buffer
-                                            let more = i < byte_count_to_process || input_available;
-
-                                            match parse_event(
-                                                &self.tty_buffer[self.tty_buffer_head_index
-                                                    ..self.tty_buffer_head_index + i],
-                                                more,
-                                            ) {
-                                                Ok(None) => {
-                                                    if i == byte_count_to_process {
-                                                        // We're at the end of buffer, just break the
-                                                        // outer while loop
-                                                        byte_count_to_process = 0;
-                                                    }
-                                                }
-                                                Ok(Some(ie)) => {
-                                                    // We've got event, push it to the queue
-                                                    self.internal_events.push_back(ie);
-
-                                                    // Increase number of consumed bytes
-                                                    consumed_bytes += i;
-                                                    // Move the head
-                                                    self.tty_buffer_head_index += i;
-                                                    // Decrease number of bytes to process
-                                                    byte_count_to_process -= i;
-                                                    // Break the inner for loop
-                                                    break;
-                                                }
-                                                Err(_) => {
-                                                    // Increase number of consumed bytes
-                                                    consumed_bytes += i;
-                                                    // Move the head
-                                                    self.tty_buffer_head_index += i;
-                                                    // Decrease number of bytes to process
-                                                    byte_count_to_process -= i;
-                                                    // Break the inner for loop
+                                        let buffer = &self.tty_buffer[self.tty_buffer_head_index
+                                            ..self.tty_buffer_head_index + byte_count_to_process];
+
+                                        // Phase one: find where the next sequence ends without
+                                        // attaching any meaning to it yet. This walks each byte
+                                        // exactly once, unlike re-invoking `parse_event` on every
+                                        // growing prefix.
+                                        let sequence_len =
+                                            match scan_sequence(buffer, input_available) {
+                                                Some(len) => len,
+                                                None => {
+                                                    // Incomplete sequence at the end of the buffer
+                                                    // and more bytes are on their way; keep it for
+                                                    // next time.
                                                     break;
                                                 }
                                             };
+
+                                        // Phase two: the span is already delimited, so `parse_event`
+                                        // can give it a meaning in one shot.
+                                        if let Ok(Some(ie)) =
+                                            parse_event(&buffer[..sequence_len], false)
+                                        {
+                                            self.internal_events.push_back(ie);
                                         }
+
+                                        // Increase number of consumed bytes
+                                        consumed_bytes += sequence_len;
+                                        // Move the head
+                                        self.tty_buffer_head_index += sequence_len;
+                                        // Decrease number of bytes to process
+                                        byte_count_to_process -= sequence_len;
                                     }
 
                                     // Update number of bytes left for future processing
@@ -300,3 +406,81 @@ impl EventSource for UnixInternalEventSource {
         let _ = self.wake_write_fd.write(&[0x57]);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::scan_sequence;
+
+    #[test]
+    fn scans_plain_ascii_byte() {
+        assert_eq!(scan_sequence(b"a", false), Some(1));
+    }
+
+    #[test]
+    fn scans_ss3_sequence() {
+        // ESC O P -> F1
+        assert_eq!(scan_sequence(b"\x1bOP", false), Some(3));
+    }
+
+    #[test]
+    fn ss3_waits_for_final_byte_when_more_is_available() {
+        assert_eq!(scan_sequence(b"\x1bO", true), None);
+    }
+
+    #[test]
+    fn scans_csi_sequence_with_params() {
+        // ESC [ 1 ; 5 D -> Ctrl+Left
+        assert_eq!(scan_sequence(b"\x1b[1;5D", false), Some(6));
+    }
+
+    #[test]
+    fn scans_legacy_mouse_report() {
+        // ESC [ M <button> <x> <y>
+        assert_eq!(scan_sequence(b"\x1b[M !!", false), Some(6));
+    }
+
+    #[test]
+    fn scans_sgr_mouse_report() {
+        // ESC [ < 0 ; 10 ; 20 M
+        assert_eq!(scan_sequence(b"\x1b[<0;10;20M", false), Some(11));
+    }
+
+    #[test]
+    fn scans_osc_terminated_by_bel() {
+        assert_eq!(scan_sequence(b"\x1b]0;title\x07", false), Some(10));
+    }
+
+    #[test]
+    fn scans_osc_terminated_by_st() {
+        assert_eq!(scan_sequence(b"\x1b]0;title\x1b\\", false), Some(11));
+    }
+
+    #[test]
+    fn scans_multi_byte_utf8_scalar() {
+        let buffer = "é".as_bytes();
+        assert_eq!(scan_sequence(buffer, false), Some(buffer.len()));
+    }
+
+    #[test]
+    fn scans_alt_plus_multi_byte_utf8_scalar() {
+        let mut buffer = vec![0x1b];
+        buffer.extend_from_slice("é".as_bytes());
+        assert_eq!(scan_sequence(&buffer, false), Some(buffer.len()));
+    }
+
+    #[test]
+    fn aborts_malformed_utf8_continuation_to_ground() {
+        // 0xC2 starts a two-byte scalar, but 'a' isn't a continuation byte.
+        assert_eq!(scan_sequence(b"\xc2a", false), Some(1));
+    }
+
+    #[test]
+    fn incomplete_sequence_waits_for_more_bytes() {
+        assert_eq!(scan_sequence(b"\x1b[1;", true), None);
+    }
+
+    #[test]
+    fn incomplete_sequence_is_flushed_when_no_more_bytes_are_coming() {
+        assert_eq!(scan_sequence(b"\x1b[1;", false), Some(4));
+    }
+}